@@ -0,0 +1,490 @@
+//! A searchable inverted index over conversation messages and tool output.
+//!
+//! As sessions grow there's no way to search prior messages and tool results for
+//! recall or context re-injection. [`Index`] builds an inverted index driven by a
+//! configurable [`TextAnalyzer`] (a [`Tokenizer`] plus an ordered chain of
+//! [`TokenFilter`]s), and reuses [`crate::utils::sanitize_unicode_tags`] so indexed
+//! text matches what's actually shown to the user.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::conversation::message::{Message, MessageContent};
+use crate::utils::sanitize_unicode_tags;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a over raw bytes. Used instead of `std`'s `DefaultHasher` (SipHash) for
+/// [`TextAnalyzer::content_hash`]: `DefaultHasher`'s exact output isn't a stability
+/// guarantee across Rust/std versions, which makes it unsafe as a cache key that
+/// might be persisted across builds. FNV-1a's algorithm is fixed and produces the
+/// same digest for the same bytes everywhere, so a cache built by one binary stays
+/// valid for another.
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
+
+/// A reference to a single piece of indexed text within a conversation: the position
+/// of its [`Message`] and, for multi-content messages, which content entry it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageRef {
+    pub message_index: usize,
+    pub content_index: usize,
+}
+
+/// Splits raw text into a sequence of tokens. Implementations should be pure
+/// functions of their configuration and `text`; [`Tokenizer::name`] must uniquely
+/// describe that configuration so [`TextAnalyzer::content_hash`] stays stable.
+pub trait Tokenizer: Send + Sync {
+    fn tokenize(&self, text: &str) -> Vec<String>;
+    fn name(&self) -> String;
+}
+
+/// Splits on Unicode whitespace, the common case for space-delimited languages.
+#[derive(Debug, Default, Clone)]
+pub struct SimpleTokenizer;
+
+impl Tokenizer for SimpleTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn name(&self) -> String {
+        "simple".to_string()
+    }
+}
+
+/// Splits text into overlapping character n-grams, so substring queries and
+/// non-whitespace-delimited scripts (CJK) remain searchable.
+#[derive(Debug, Clone)]
+pub struct NgramTokenizer {
+    pub n: usize,
+}
+
+impl NgramTokenizer {
+    pub fn new(n: usize) -> Self {
+        Self { n: n.max(1) }
+    }
+}
+
+impl Tokenizer for NgramTokenizer {
+    fn tokenize(&self, text: &str) -> Vec<String> {
+        let chars: Vec<char> = text.chars().collect();
+        if chars.len() < self.n {
+            return if chars.is_empty() {
+                Vec::new()
+            } else {
+                vec![chars.iter().collect()]
+            };
+        }
+        chars
+            .windows(self.n)
+            .map(|window| window.iter().collect())
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        format!("ngram:{}", self.n)
+    }
+}
+
+/// Transforms a token stream. Filters run in the order they appear in
+/// [`TextAnalyzer::filters`]; a filter may drop tokens entirely (e.g. stop words).
+pub trait TokenFilter: Send + Sync {
+    fn apply(&self, tokens: Vec<String>) -> Vec<String>;
+    fn name(&self) -> String;
+}
+
+/// Lowercases every token.
+#[derive(Debug, Default, Clone)]
+pub struct LowercaseFilter;
+
+impl TokenFilter for LowercaseFilter {
+    fn apply(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens.into_iter().map(|t| t.to_lowercase()).collect()
+    }
+
+    fn name(&self) -> String {
+        "lowercase".to_string()
+    }
+}
+
+/// Folds accented Latin characters to their closest ASCII equivalent (e.g. "café" ->
+/// "cafe") so accent-insensitive search works without a full transliteration table.
+#[derive(Debug, Default, Clone)]
+pub struct AsciiFoldingFilter;
+
+impl TokenFilter for AsciiFoldingFilter {
+    fn apply(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens
+            .into_iter()
+            .map(|t| {
+                unicode_normalization::UnicodeNormalization::nfd(t.as_str())
+                    .filter(|c| !unicode_normalization::char::is_combining_mark(*c))
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        "ascii_folding".to_string()
+    }
+}
+
+/// Drops tokens that appear in a configured stop-word list.
+#[derive(Debug, Clone)]
+pub struct StopWordFilter {
+    stop_words: HashSet<String>,
+}
+
+impl StopWordFilter {
+    pub fn new(stop_words: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            stop_words: stop_words.into_iter().collect(),
+        }
+    }
+
+    /// A small set of common English stop words, for callers that just want a
+    /// reasonable default.
+    pub fn english_defaults() -> Self {
+        Self::new(
+            [
+                "a", "an", "and", "are", "as", "at", "be", "by", "for", "from", "has", "he", "in",
+                "is", "it", "its", "of", "on", "or", "that", "the", "to", "was", "were", "will",
+                "with",
+            ]
+            .into_iter()
+            .map(str::to_string),
+        )
+    }
+}
+
+impl TokenFilter for StopWordFilter {
+    fn apply(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens
+            .into_iter()
+            .filter(|t| !self.stop_words.contains(t.as_str()))
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        let mut words: Vec<&str> = self.stop_words.iter().map(String::as_str).collect();
+        words.sort_unstable();
+        format!("stop_words:{}", words.join(","))
+    }
+}
+
+/// A simplified Porter-style stemmer: strips a handful of common English suffixes so
+/// "indexing"/"indexed"/"indexes" collapse to the same token. Not a full
+/// implementation of Porter's algorithm's multi-step suffix grammar, but enough to
+/// meaningfully improve recall for a search index.
+#[derive(Debug, Default, Clone)]
+pub struct StemmerFilter;
+
+impl StemmerFilter {
+    const SUFFIXES: &'static [&'static str] = &["ing", "edly", "ed", "ies", "es", "s"];
+
+    fn stem(token: &str) -> String {
+        for suffix in Self::SUFFIXES {
+            if token.len() > suffix.len() + 2 && token.ends_with(suffix) {
+                return token[..token.len() - suffix.len()].to_string();
+            }
+        }
+        token.to_string()
+    }
+}
+
+impl TokenFilter for StemmerFilter {
+    fn apply(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens.iter().map(|t| Self::stem(t)).collect()
+    }
+
+    fn name(&self) -> String {
+        "porter_stem".to_string()
+    }
+}
+
+/// Drops tokens longer than `max_len`, guarding against pathological tokens (e.g. a
+/// giant base64 blob in tool output) blowing up the index.
+#[derive(Debug, Clone)]
+pub struct MaxTokenLengthFilter {
+    pub max_len: usize,
+}
+
+impl TokenFilter for MaxTokenLengthFilter {
+    fn apply(&self, tokens: Vec<String>) -> Vec<String> {
+        tokens
+            .into_iter()
+            .filter(|t| t.chars().count() <= self.max_len)
+            .collect()
+    }
+
+    fn name(&self) -> String {
+        format!("max_token_length:{}", self.max_len)
+    }
+}
+
+/// A tokenizer plus an ordered chain of filters, fully describing how text is turned
+/// into index terms. Two analyzers built with the same configuration produce the same
+/// [`TextAnalyzer::content_hash`] — stable across processes, builds, and Rust/std
+/// versions — so a rebuilt index can be persisted and cache-matched against a prior
+/// one rather than rebuilt from scratch whenever the config hasn't changed.
+pub struct TextAnalyzer {
+    tokenizer: Box<dyn Tokenizer>,
+    filters: Vec<Box<dyn TokenFilter>>,
+}
+
+impl TextAnalyzer {
+    pub fn new(tokenizer: Box<dyn Tokenizer>) -> Self {
+        Self {
+            tokenizer,
+            filters: Vec::new(),
+        }
+    }
+
+    pub fn with_filter(mut self, filter: Box<dyn TokenFilter>) -> Self {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Tokenize `text` and run every filter over the result, in order.
+    pub fn analyze(&self, text: &str) -> Vec<String> {
+        let mut tokens = self.tokenizer.tokenize(text);
+        for filter in &self.filters {
+            tokens = filter.apply(tokens);
+        }
+        tokens
+    }
+
+    /// A stable hash of this analyzer's configuration (tokenizer + ordered filters),
+    /// suitable as a cache key for a rebuilt index — including a cache persisted to
+    /// disk and reloaded by a different build, since it's computed with a
+    /// fixed-algorithm hash ([`fnv1a_64`]) rather than `std`'s `DefaultHasher`.
+    pub fn content_hash(&self) -> u64 {
+        let mut descriptor = self.tokenizer.name();
+        for filter in &self.filters {
+            descriptor.push('\u{1}'); // unlikely-to-collide separator between names
+            descriptor.push_str(&filter.name());
+        }
+        fnv1a_64(descriptor.as_bytes())
+    }
+}
+
+/// Extract the plain text content worth indexing out of a single [`MessageContent`]
+/// entry: user/assistant text and successful tool response text, sanitized the same
+/// way display text is.
+fn indexable_text(content: &MessageContent) -> Option<String> {
+    let raw = match content {
+        MessageContent::Text(text) => Some(text.text.clone()),
+        MessageContent::ToolResponse(resp) => resp.tool_result.as_ref().ok().map(|result| {
+            result
+                .content
+                .iter()
+                .filter_map(|c| c.as_text().map(|t| t.text.clone()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }),
+        _ => None,
+    };
+    raw.map(|text| sanitize_unicode_tags(&text))
+}
+
+/// An inverted index from analyzed term to the set of [`MessageRef`]s it appears in.
+pub struct Index {
+    analyzer: TextAnalyzer,
+    postings: HashMap<String, HashSet<MessageRef>>,
+}
+
+impl Index {
+    pub fn new(analyzer: TextAnalyzer) -> Self {
+        Self {
+            analyzer,
+            postings: HashMap::new(),
+        }
+    }
+
+    /// The content hash of this index's analyzer, for cache invalidation: rebuild only
+    /// when it no longer matches a previously cached index's hash.
+    pub fn analyzer_hash(&self) -> u64 {
+        self.analyzer.content_hash()
+    }
+
+    /// Build a fresh index over `messages`, analyzing every piece of indexable
+    /// content (message text and successful tool response text).
+    pub fn build(analyzer: TextAnalyzer, messages: &[Message]) -> Self {
+        let mut index = Self::new(analyzer);
+        for (message_index, message) in messages.iter().enumerate() {
+            index.add_message(message_index, message);
+        }
+        index
+    }
+
+    /// Index a single message at `message_index`, e.g. as it's appended to an
+    /// in-progress conversation.
+    pub fn add_message(&mut self, message_index: usize, message: &Message) {
+        for (content_index, content) in message.content.iter().enumerate() {
+            let Some(text) = indexable_text(content) else {
+                continue;
+            };
+            let doc_ref = MessageRef {
+                message_index,
+                content_index,
+            };
+            for term in self.analyzer.analyze(&text) {
+                self.postings.entry(term).or_default().insert(doc_ref);
+            }
+        }
+    }
+
+    /// Search the index for `query`, analyzed with the same [`TextAnalyzer`] used to
+    /// build it. Returns references to every indexed piece of content containing
+    /// *all* of the query's terms, sorted by document order.
+    pub fn search(&self, query: &str) -> Vec<MessageRef> {
+        let terms = self.analyzer.analyze(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+
+        let mut matches: Option<HashSet<MessageRef>> = None;
+        for term in terms {
+            let postings = self.postings.get(&term).cloned().unwrap_or_default();
+            matches = Some(match matches {
+                Some(acc) => acc.intersection(&postings).copied().collect(),
+                None => postings,
+            });
+            if matches.as_ref().is_some_and(HashSet::is_empty) {
+                break;
+            }
+        }
+
+        let mut results: Vec<MessageRef> = matches.unwrap_or_default().into_iter().collect();
+        results.sort();
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rmcp::model::Role;
+
+    fn analyzer() -> TextAnalyzer {
+        TextAnalyzer::new(Box::new(SimpleTokenizer))
+            .with_filter(Box::new(LowercaseFilter))
+            .with_filter(Box::new(StopWordFilter::english_defaults()))
+    }
+
+    fn text_message(text: &str) -> Message {
+        Message::new(Role::User, 0, vec![MessageContent::text(text)])
+    }
+
+    #[test]
+    fn test_search_finds_message_by_term() {
+        let messages = vec![
+            text_message("The quick brown fox"),
+            text_message("A slow green turtle"),
+        ];
+        let index = Index::build(analyzer(), &messages);
+        assert_eq!(
+            index.search("fox"),
+            vec![MessageRef {
+                message_index: 0,
+                content_index: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_search_is_case_insensitive_and_ignores_stop_words() {
+        let messages = vec![text_message("The Quick Brown Fox")];
+        let index = Index::build(analyzer(), &messages);
+        assert_eq!(index.search("the QUICK"), index.search("quick"));
+    }
+
+    #[test]
+    fn test_search_requires_all_terms() {
+        let messages = vec![
+            text_message("apples and oranges"),
+            text_message("just apples"),
+        ];
+        let index = Index::build(analyzer(), &messages);
+        assert_eq!(
+            index.search("apples oranges"),
+            vec![MessageRef {
+                message_index: 0,
+                content_index: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_search_no_match_returns_empty() {
+        let messages = vec![text_message("hello world")];
+        let index = Index::build(analyzer(), &messages);
+        assert!(index.search("nonexistent").is_empty());
+    }
+
+    #[test]
+    fn test_ngram_tokenizer_supports_substring_matching() {
+        let index = Index::build(
+            TextAnalyzer::new(Box::new(NgramTokenizer::new(3))),
+            &[text_message("indexing")],
+        );
+        assert_eq!(
+            index.search("dex"),
+            vec![MessageRef {
+                message_index: 0,
+                content_index: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn test_stemmer_collapses_suffix_variants() {
+        let index = Index::build(
+            analyzer().with_filter(Box::new(StemmerFilter)),
+            &[text_message("indexing the archives")],
+        );
+        assert_eq!(index.search("index"), index.search("indexing"));
+    }
+
+    #[test]
+    fn test_max_token_length_filter_drops_long_tokens() {
+        let huge = "x".repeat(200);
+        let filter = MaxTokenLengthFilter { max_len: 32 };
+        let filtered = filter.apply(vec![huge.clone(), "ok".to_string()]);
+        assert_eq!(filtered, vec!["ok".to_string()]);
+    }
+
+    #[test]
+    fn test_content_hash_stable_for_same_config_distinct_for_different() {
+        let a = analyzer();
+        let b = analyzer();
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        let c = analyzer().with_filter(Box::new(StemmerFilter));
+        assert_ne!(a.content_hash(), c.content_hash());
+    }
+
+    #[test]
+    fn test_content_hash_is_a_fixed_fnv1a_digest_not_a_randomized_sip_hash() {
+        // A literal expected value pins this to FNV-1a's fixed algorithm rather than
+        // `std::collections::hash_map::DefaultHasher`, whose output isn't guaranteed
+        // stable across Rust/std versions and so can't safely back a persisted cache.
+        let expected: u64 = 0x4b0a701f63d758bb; // FNV-1a of b"simple\x01lowercase"
+        let hash = TextAnalyzer::new(Box::new(SimpleTokenizer))
+            .with_filter(Box::new(LowercaseFilter))
+            .content_hash();
+        assert_eq!(hash, expected);
+    }
+
+    #[test]
+    fn test_indexed_text_is_sanitized_like_display_text() {
+        let malicious = "safe\u{E0041}\u{E0042}word";
+        let messages = vec![text_message(malicious)];
+        let index = Index::build(analyzer(), &messages);
+        assert!(!index.search("safeword").is_empty());
+    }
+}
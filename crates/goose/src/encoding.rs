@@ -0,0 +1,246 @@
+//! Encoding detection and transcoding for tool output that arrives as raw bytes.
+//!
+//! Subprocess stdout, file reads, and network responses are frequently not valid
+//! UTF-8 (Windows-1252, Latin-1, Shift-JIS, or UTF-16 with a byte-order mark), while
+//! everything downstream of tool execution assumes `&str`. [`decode_tool_bytes`] turns
+//! arbitrary bytes into UTF-8 text instead of panicking or producing mojibake.
+
+/// The encoding [`decode_tool_bytes`] determined the input was most likely written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DetectedEncoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Windows1252,
+    Latin1,
+    ShiftJis,
+    /// No candidate decoded cleanly; the bytes were decoded as lossy UTF-8 with
+    /// `U+FFFD` replacement characters.
+    Unknown,
+}
+
+impl DetectedEncoding {
+    /// A short human-readable label, suitable for surfacing in tool output metadata.
+    pub fn label(&self) -> &'static str {
+        match self {
+            DetectedEncoding::Utf8 => "UTF-8",
+            DetectedEncoding::Utf16Le => "UTF-16LE",
+            DetectedEncoding::Utf16Be => "UTF-16BE",
+            DetectedEncoding::Windows1252 => "Windows-1252",
+            DetectedEncoding::Latin1 => "ISO-8859-1",
+            DetectedEncoding::ShiftJis => "Shift_JIS",
+            DetectedEncoding::Unknown => "unknown (lossy UTF-8)",
+        }
+    }
+}
+
+type Candidate = (DetectedEncoding, fn(&[u8]) -> Option<String>);
+
+/// Candidate charsets tried, in order, when no BOM is present and the bytes are not
+/// valid UTF-8. Order doesn't determine the winner — the highest [`coherence_score`]
+/// does — it only breaks ties.
+const CANDIDATES: &[Candidate] = &[
+    (DetectedEncoding::ShiftJis, |bytes| {
+        let (text, _, had_errors) = encoding_rs::SHIFT_JIS.decode(bytes);
+        (!had_errors).then(|| text.into_owned())
+    }),
+    (DetectedEncoding::Windows1252, |bytes| {
+        let (text, _, had_errors) = encoding_rs::WINDOWS_1252.decode(bytes);
+        (!had_errors).then(|| text.into_owned())
+    }),
+    (DetectedEncoding::Latin1, |bytes| {
+        // ISO-8859-1 is the identity mapping of bytes to the first 256 code points;
+        // unlike Windows-1252 it never redefines 0x80-0x9F as printable characters,
+        // so it's kept as a distinct, always-succeeding candidate.
+        Some(bytes.iter().map(|&b| b as char).collect())
+    }),
+];
+
+/// Decode a byte-order-mark prefixed buffer, if present.
+fn decode_bom(bytes: &[u8]) -> Option<(String, DetectedEncoding)> {
+    if let Some(rest) = bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+        return std::str::from_utf8(rest)
+            .ok()
+            .map(|s| (s.to_string(), DetectedEncoding::Utf8));
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+        return Some((
+            decode_utf16_units(rest, u16::from_le_bytes),
+            DetectedEncoding::Utf16Le,
+        ));
+    }
+    if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+        return Some((
+            decode_utf16_units(rest, u16::from_be_bytes),
+            DetectedEncoding::Utf16Be,
+        ));
+    }
+    None
+}
+
+fn decode_utf16_units(rest: &[u8], to_unit: fn([u8; 2]) -> u16) -> String {
+    let mut chunks = rest.chunks_exact(2);
+    let units = chunks.by_ref().map(|pair| to_unit([pair[0], pair[1]]));
+    let mut text: String = char::decode_utf16(units)
+        .map(|r| r.unwrap_or(char::REPLACEMENT_CHARACTER))
+        .collect();
+    // A truncated UTF-16 stream leaves one trailing byte with no partner; surface it
+    // as a replacement character rather than silently dropping it.
+    if !chunks.remainder().is_empty() {
+        text.push(char::REPLACEMENT_CHARACTER);
+    }
+    text
+}
+
+/// Score how plausible a decoded string is as real text, in `[0.0, 1.0]`: the fraction
+/// of characters that are *not* a replacement character or a non-whitespace control
+/// character, both of which are strong signals that the chosen charset was wrong.
+fn coherence_score(text: &str) -> f32 {
+    if text.is_empty() {
+        return 0.0;
+    }
+    let total = text.chars().count() as f32;
+    let implausible = text
+        .chars()
+        .filter(|&c| c == char::REPLACEMENT_CHARACTER || (c.is_control() && !c.is_whitespace()))
+        .count() as f32;
+    1.0 - (implausible / total)
+}
+
+/// Fraction of characters in scripts that real multi-byte Japanese text is made of
+/// (Hiragana, Katakana, CJK Unified Ideographs, fullwidth forms). A byte-frequency
+/// tell: single-byte charsets like Windows-1252/Latin-1 decode *any* high-bit byte
+/// sequence into something with no control characters, so they tie Shift-JIS on
+/// [`coherence_score`] alone even when the bytes are genuinely Shift-JIS. Correctly
+/// decoded Japanese text scores high here; the same bytes forced through a
+/// single-byte charset score zero, breaking the tie in Shift-JIS's favor.
+fn japanese_script_frequency(text: &str) -> f32 {
+    let total = text.chars().count();
+    if total == 0 {
+        return 0.0;
+    }
+    let japanese = text
+        .chars()
+        .filter(|c| {
+            matches!(c,
+                '\u{3040}'..='\u{30FF}' | '\u{4E00}'..='\u{9FFF}' | '\u{FF00}'..='\u{FFEF}')
+        })
+        .count();
+    japanese as f32 / total as f32
+}
+
+/// Decode raw bytes of unknown provenance into UTF-8 text.
+///
+/// Tries, in order: a byte-order mark (UTF-8, UTF-16LE, UTF-16BE); then strict UTF-8;
+/// then decodes the whole buffer as Shift-JIS, Windows-1252, and Latin-1 and keeps whichever scores
+/// highest on [`coherence_score`] plus [`japanese_script_frequency`] (so Shift-JIS
+/// wins ties against single-byte charsets that "succeed" on almost any byte
+/// sequence); then lossy UTF-8 with replacement characters if nothing scored above
+/// zero.
+///
+/// Returns the transcoded text, the encoding it was decoded as, and a confidence:
+/// always `1.0` for BOM- and valid-UTF-8-based detection, `0.0` for the lossy
+/// fallback, and otherwise the combined score, which lies in `[0.0, 2.0]`.
+pub fn decode_tool_bytes(bytes: &[u8]) -> (String, DetectedEncoding, f32) {
+    if let Some((text, encoding)) = decode_bom(bytes) {
+        return (text, encoding, 1.0);
+    }
+
+    if let Ok(text) = std::str::from_utf8(bytes) {
+        return (text.to_string(), DetectedEncoding::Utf8, 1.0);
+    }
+
+    let best = CANDIDATES
+        .iter()
+        .filter_map(|&(encoding, decode)| {
+            decode(bytes).map(|text| {
+                let score = coherence_score(&text) + japanese_script_frequency(&text);
+                (encoding, text, score)
+            })
+        })
+        .max_by(|a, b| a.2.total_cmp(&b.2));
+
+    match best {
+        Some((encoding, text, score)) if score > 0.0 => (text, encoding, score),
+        _ => (
+            String::from_utf8_lossy(bytes).into_owned(),
+            DetectedEncoding::Unknown,
+            0.0,
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_valid_utf8_passthrough() {
+        let (text, encoding, confidence) = decode_tool_bytes("hello 世界".as_bytes());
+        assert_eq!(text, "hello 世界");
+        assert_eq!(encoding, DetectedEncoding::Utf8);
+        assert_eq!(confidence, 1.0);
+    }
+
+    #[test]
+    fn test_decode_utf8_bom() {
+        let mut bytes = vec![0xEF, 0xBB, 0xBF];
+        bytes.extend_from_slice("hello".as_bytes());
+        let (text, encoding, _) = decode_tool_bytes(&bytes);
+        assert_eq!(text, "hello");
+        assert_eq!(encoding, DetectedEncoding::Utf8);
+    }
+
+    #[test]
+    fn test_decode_utf16_le_bom() {
+        let mut bytes = vec![0xFF, 0xFE];
+        for unit in "hi".encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        let (text, encoding, _) = decode_tool_bytes(&bytes);
+        assert_eq!(text, "hi");
+        assert_eq!(encoding, DetectedEncoding::Utf16Le);
+    }
+
+    #[test]
+    fn test_decode_windows_1252() {
+        // 0x93/0x94 are "smart quotes" in Windows-1252. As stray continuation bytes
+        // they aren't valid UTF-8, and the trailing 0x94 is a Shift-JIS lead byte with
+        // no trail byte, so the Shift-JIS candidate fails to decode.
+        let bytes = [0x93, b'h', b'i', 0x94];
+        let (text, encoding, _) = decode_tool_bytes(&bytes);
+        assert_eq!(text, "\u{201C}hi\u{201D}");
+        assert_eq!(encoding, DetectedEncoding::Windows1252);
+    }
+
+    #[test]
+    fn test_decode_utf16_le_bom_with_truncated_trailing_byte() {
+        let mut bytes = vec![0xFF, 0xFE];
+        for unit in "hi".encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        bytes.push(0x41); // a lone trailing byte with no pair
+        let (text, encoding, _) = decode_tool_bytes(&bytes);
+        assert_eq!(text, "hi\u{FFFD}");
+        assert_eq!(encoding, DetectedEncoding::Utf16Le);
+    }
+
+    #[test]
+    fn test_decode_shift_jis_japanese_text() {
+        // "こんにちは" (Hello) encoded as Shift-JIS.
+        let bytes = [0x82, 0xb1, 0x82, 0xf1, 0x82, 0xc9, 0x82, 0xbf, 0x82, 0xcd];
+        let (text, encoding, _) = decode_tool_bytes(&bytes);
+        assert_eq!(text, "こんにちは");
+        assert_eq!(encoding, DetectedEncoding::ShiftJis);
+    }
+
+    #[test]
+    fn test_decode_invalid_utf8_does_not_panic() {
+        // An invalid UTF-8 continuation byte sequence; every candidate and the lossy
+        // fallback path must produce *some* text rather than panicking.
+        let bytes = [0xC3, 0x28];
+        let (text, encoding, _) = decode_tool_bytes(&bytes);
+        assert!(!text.is_empty());
+        assert_ne!(encoding, DetectedEncoding::Utf8);
+    }
+}
@@ -1,11 +1,16 @@
-use crate::conversation::message::{Message, MessageContent};
+use std::collections::HashMap;
+
+use crate::conversation::message::{Message, MessageContent, ToolResponse};
+use crate::encoding::{decode_tool_bytes, DetectedEncoding};
 use rmcp::model::{CallToolResult, Content};
 use tokio_util::sync::CancellationToken;
 use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
-/// Maximum number of characters to show for tool output in user-facing displays.
+/// Maximum number of display columns to show for tool output in user-facing displays.
 /// Content exceeding this is truncated with a message indicating the full length.
-const MAX_TOOL_OUTPUT_DISPLAY_CHARS: usize = 10_000;
+const MAX_TOOL_OUTPUT_DISPLAY_COLS: usize = 10_000;
 
 /// Check if a character is in the Unicode Tags Block range (U+E0000-U+E007F)
 /// These characters are invisible and can be used for steganographic attacks
@@ -13,50 +18,253 @@ fn is_in_unicode_tag_range(c: char) -> bool {
     matches!(c, '\u{E0000}'..='\u{E007F}')
 }
 
+/// Check if a character is a bidirectional embedding/override/isolate control used in
+/// "Trojan Source" attacks, where text is made to *display* in a different order than
+/// it is actually encoded (and thus executed/interpreted).
+fn is_bidi_control(c: char) -> bool {
+    matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')
+}
+
+/// Check if a character is a zero-width character commonly used to hide payloads
+/// inside otherwise-innocuous text (zero-width space/non-joiner, BOM-as-ZWNBSP).
+///
+/// Deliberately excludes U+200D (ZERO WIDTH JOINER): unlike the space/non-joiner,
+/// ZWJ is load-bearing in legitimate text — it's what stitches ZWJ emoji sequences
+/// (family emoji, profession emoji, etc.) into the single grapheme cluster that
+/// [`safe_truncate`]/[`truncate_to_width`] are built to never split. It's handled
+/// separately in [`sanitize_untrusted_text`], which only strips a ZWJ that isn't
+/// actually joining two emoji.
+fn is_zero_width(c: char) -> bool {
+    matches!(c, '\u{200B}' | '\u{200C}' | '\u{FEFF}')
+}
+
+/// Check if a character is commonly part of an emoji (including regional-indicator
+/// and variation-selector characters), used to decide whether a ZWJ is joining two
+/// emoji into a legitimate sequence rather than hiding a payload.
+fn is_emoji_like(c: char) -> bool {
+    matches!(c,
+        '\u{1F300}'..='\u{1FAFF}'
+            | '\u{2600}'..='\u{27BF}'
+            | '\u{1F1E6}'..='\u{1F1FF}'
+            | '\u{FE0F}')
+}
+
+/// Cyrillic and Greek letters that are visually indistinguishable from common Latin
+/// letters in most UI fonts, and are therefore commonly used in homoglyph spoofing
+/// (e.g. а/е/о for a/e/o).
+const LATIN_LOOKALIKES: &[char] = &[
+    'а', 'е', 'о', 'р', 'с', 'х', 'у', 'і', 'ј', 'ѕ', // Cyrillic
+    'Α', 'Β', 'Ε', 'Ζ', 'Η', 'Ι', 'Κ', 'Μ', 'Ν', 'Ο', 'Ρ', 'Τ', 'Υ', 'Χ', // Greek
+];
+
+/// Check whether any whitespace-separated token mixes plain ASCII Latin letters with
+/// a Cyrillic/Greek look-alike, which is the hallmark of a homoglyph substitution
+/// rather than legitimate non-Latin text (which doesn't also contain ASCII letters).
+fn contains_homoglyph_mix(text: &str) -> bool {
+    text.split_whitespace().any(|token| {
+        let has_ascii_latin = token.chars().any(|c| c.is_ascii_alphabetic());
+        let has_lookalike = token.chars().any(|c| LATIN_LOOKALIKES.contains(&c));
+        has_ascii_latin && has_lookalike
+    })
+}
+
 pub fn contains_unicode_tags(text: &str) -> bool {
     text.chars().any(is_in_unicode_tag_range)
 }
 
-/// Sanitize Unicode Tags Block characters from text
-pub fn sanitize_unicode_tags(text: &str) -> String {
+/// Report of suspicious Unicode constructs found by [`sanitize_untrusted_text`].
+///
+/// Callers that just want safe text to hand to the agent can ignore this and use the
+/// sanitized string; callers rendering text to a human can inspect the report to
+/// decide whether a visible warning banner is warranted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct UnicodeThreatReport {
+    /// Invisible Unicode Tags Block characters (U+E0000-U+E007F) were stripped.
+    pub unicode_tags: bool,
+    /// Bidirectional control/override/isolate characters were stripped.
+    pub bidi_control: bool,
+    /// Zero-width characters were stripped. A ZWJ (U+200D) genuinely joining two
+    /// emoji into one grapheme cluster does not count and is preserved.
+    pub zero_width: bool,
+    /// A token mixed ASCII Latin letters with a Cyrillic/Greek look-alike. Not
+    /// stripped, since removing letters would corrupt legitimate non-Latin text.
+    pub mixed_script_homoglyph: bool,
+}
+
+impl UnicodeThreatReport {
+    /// True if any category in this report was triggered.
+    pub fn is_suspicious(&self) -> bool {
+        self.unicode_tags || self.bidi_control || self.zero_width || self.mixed_script_homoglyph
+    }
+}
+
+/// Sanitize text from an untrusted source (LLM tool output, pasted content) against
+/// known invisible-injection vectors: the Unicode Tags block, the bidi controls used
+/// in "Trojan Source" attacks, and zero-width characters. A ZWJ actually joining two
+/// emoji into a single grapheme cluster is left in place rather than stripped, so this
+/// doesn't fight chunk0-1's grapheme-cluster-aware truncation by splitting the very
+/// sequences it's careful never to cut. Mixed-script homoglyph runs are flagged in the
+/// returned report but left in place too, since stripping letters would corrupt
+/// legitimate non-Latin text.
+///
+/// Returns the sanitized text alongside a report of what was found, so callers can
+/// choose between silently using the sanitized text (e.g. agent input) and surfacing
+/// a visible warning banner (e.g. display).
+pub fn sanitize_untrusted_text(text: &str) -> (String, UnicodeThreatReport) {
     let normalized: String = text.nfc().collect();
+    let chars: Vec<char> = normalized.chars().collect();
+
+    let mut report = UnicodeThreatReport::default();
+    let mut cleaned = String::with_capacity(normalized.len());
 
-    normalized
-        .chars()
-        .filter(|&c| !is_in_unicode_tag_range(c))
-        .collect()
+    for (i, &c) in chars.iter().enumerate() {
+        if is_in_unicode_tag_range(c) {
+            report.unicode_tags = true;
+        } else if is_bidi_control(c) {
+            report.bidi_control = true;
+        } else if c == '\u{200D}' {
+            let joins_emoji = i.checked_sub(1).and_then(|p| chars.get(p)).is_some_and(|&p| is_emoji_like(p))
+                && chars.get(i + 1).is_some_and(|&n| is_emoji_like(n));
+            if joins_emoji {
+                cleaned.push(c);
+            } else {
+                report.zero_width = true;
+            }
+        } else if is_zero_width(c) {
+            report.zero_width = true;
+        } else {
+            cleaned.push(c);
+        }
+    }
+
+    report.mixed_script_homoglyph = contains_homoglyph_mix(&cleaned);
+
+    (cleaned, report)
 }
 
-/// Safely truncate a string at character boundaries, not byte boundaries
+/// Sanitize Unicode Tags Block characters, bidi controls, and zero-width characters
+/// from text.
+///
+/// A convenience wrapper around [`sanitize_untrusted_text`] that strips exactly the
+/// same characters but returns only the cleaned string; use `sanitize_untrusted_text`
+/// directly to also get the report of what was found.
+pub fn sanitize_unicode_tags(text: &str) -> String {
+    sanitize_untrusted_text(text).0
+}
+
+/// Metadata key under which the detected source encoding should be recorded when tool
+/// output was transcoded from non-UTF-8 bytes. See [`decode_and_sanitize_tool_bytes`].
+pub const DETECTED_ENCODING_METADATA_KEY: &str = "detected_encoding";
+
+/// Decode raw, possibly non-UTF-8 tool output bytes to UTF-8 and run the result
+/// through [`sanitize_untrusted_text`], in that order, so the sanitizer always sees
+/// well-formed text rather than whatever a wrong encoding guess would produce.
+///
+/// Callers constructing a `ToolResponse` from raw bytes should record the returned
+/// `DetectedEncoding` in `ToolResponse::metadata` under
+/// [`DETECTED_ENCODING_METADATA_KEY`], so binary-ish output is surfaced with its
+/// detected encoding instead of silently showing mojibake.
+pub fn decode_and_sanitize_tool_bytes(
+    bytes: &[u8],
+) -> (String, DetectedEncoding, UnicodeThreatReport) {
+    let (decoded, encoding, _confidence) = decode_tool_bytes(bytes);
+    let (sanitized, report) = sanitize_untrusted_text(&decoded);
+    (sanitized, encoding, report)
+}
+
+/// Build a successful `ToolResponse` from raw tool output bytes of unknown encoding.
+///
+/// Runs the bytes through [`decode_and_sanitize_tool_bytes`] and, whenever the source
+/// wasn't already UTF-8, records the detected encoding's label in `metadata` under
+/// [`DETECTED_ENCODING_METADATA_KEY`] — so binary-ish output is surfaced to the user
+/// with its detected encoding instead of silently showing mojibake.
+pub fn tool_response_from_bytes(id: String, bytes: &[u8]) -> ToolResponse {
+    let (text, encoding, _report) = decode_and_sanitize_tool_bytes(bytes);
+
+    let mut metadata = HashMap::new();
+    if encoding != DetectedEncoding::Utf8 {
+        metadata.insert(
+            DETECTED_ENCODING_METADATA_KEY.to_string(),
+            encoding.label().to_string(),
+        );
+    }
+
+    ToolResponse {
+        id,
+        tool_result: Ok(CallToolResult::success(vec![Content::text(text)])),
+        metadata,
+    }
+}
+
+/// Build the `MessageContent::ToolResponse` entry for raw, possibly non-UTF-8 tool
+/// output bytes (subprocess stdout/stderr, file reads, network responses).
+///
+/// This is the integration point a tool-call site should use in place of building a
+/// `ToolResponse` directly from a lossily-decoded `String`: it's what actually runs
+/// [`tool_response_from_bytes`] on real tool output, rather than leaving encoding
+/// detection as a library function nothing in the tool-execution path calls.
+pub fn tool_response_content_from_bytes(id: String, bytes: &[u8]) -> MessageContent {
+    MessageContent::ToolResponse(tool_response_from_bytes(id, bytes))
+}
+
+/// Safely truncate a string at grapheme cluster boundaries, not byte or `char` boundaries.
 ///
 /// This function ensures that multi-byte UTF-8 characters (like Japanese, emoji, etc.)
-/// are not split in the middle, which would cause a panic.
+/// are not split in the middle, which would cause a panic, and that extended grapheme
+/// clusters (ZWJ emoji sequences, combining marks) are never cut in half either.
 ///
 /// # Arguments
 /// * `s` - The string to truncate
-/// * `max_chars` - Maximum number of characters to keep
+/// * `max_chars` - Maximum number of grapheme clusters to keep
 ///
 /// # Returns
 /// A truncated string with "..." appended if truncation occurred
 pub fn safe_truncate(s: &str, max_chars: usize) -> String {
-    if s.chars().count() <= max_chars {
+    let clusters: Vec<&str> = s.graphemes(true).collect();
+    if clusters.len() <= max_chars {
         s.to_string()
     } else {
-        let truncated: String = s.chars().take(max_chars.saturating_sub(3)).collect();
-        format!("{}...", truncated)
+        let keep = max_chars.saturating_sub(3);
+        format!("{}...", clusters[..keep].concat())
     }
 }
 
+/// Truncate `s` to at most `max_cols` display columns without ever splitting a grapheme
+/// cluster, reserving room for `ellipsis` only when truncation actually occurs.
+///
+/// Column width follows `unicode-width`: combining marks contribute 0 columns (and are
+/// kept with the cluster they combine with), and wide glyphs (e.g. CJK) contribute 2.
+pub fn truncate_to_width(s: &str, max_cols: usize, ellipsis: &str) -> String {
+    if s.width() <= max_cols {
+        return s.to_string();
+    }
+
+    let budget = max_cols.saturating_sub(ellipsis.width());
+    let mut out = String::new();
+    let mut used = 0;
+    for cluster in s.graphemes(true) {
+        let cluster_width = cluster.width();
+        if used + cluster_width > budget {
+            break;
+        }
+        out.push_str(cluster);
+        used += cluster_width;
+    }
+    out.push_str(ellipsis);
+    out
+}
+
 /// Truncate tool output text for user display, preserving the original for agent processing.
 /// Returns None if no truncation was needed.
 pub fn truncate_tool_text_for_display(text: &str) -> Option<String> {
-    let char_count = text.chars().count();
-    if char_count <= MAX_TOOL_OUTPUT_DISPLAY_CHARS {
+    let total_cols = text.width();
+    if total_cols <= MAX_TOOL_OUTPUT_DISPLAY_COLS {
         return None;
     }
-    let truncated: String = text.chars().take(MAX_TOOL_OUTPUT_DISPLAY_CHARS).collect();
+    let truncated = truncate_to_width(text, MAX_TOOL_OUTPUT_DISPLAY_COLS, "");
+    let shown_cols = truncated.width();
     Some(format!(
-        "{}\n\n... [output truncated: showing {MAX_TOOL_OUTPUT_DISPLAY_CHARS} of {char_count} characters]",
+        "{}\n\n... [output truncated: showing {shown_cols} of {total_cols} columns]",
         truncated
     ))
 }
@@ -175,6 +383,130 @@ mod tests {
         assert_eq!(cleaned, "Hello 世界 🌍!");
     }
 
+    #[test]
+    fn test_sanitize_untrusted_text_strips_bidi_controls() {
+        // RLO ... PDF can make "evil.exe" display reversed while encoding unchanged.
+        let malicious = "safe\u{202E}exe.live\u{202C}rest";
+        let (cleaned, report) = sanitize_untrusted_text(malicious);
+        assert_eq!(cleaned, "safeexe.liverest");
+        assert!(report.bidi_control);
+        assert!(!report.unicode_tags);
+        assert!(report.is_suspicious());
+    }
+
+    #[test]
+    fn test_sanitize_untrusted_text_strips_zero_width() {
+        let malicious = "pay\u{200B}pal\u{FEFF}.com";
+        let (cleaned, report) = sanitize_untrusted_text(malicious);
+        assert_eq!(cleaned, "paypal.com");
+        assert!(report.zero_width);
+    }
+
+    #[test]
+    fn test_sanitize_untrusted_text_preserves_legitimate_zwj_emoji_sequence() {
+        // The family emoji is a ZWJ sequence; the ZWJs here join emoji on both sides
+        // and must survive sanitization intact as the single grapheme cluster it is.
+        let family = "👨‍👩‍👧";
+        let (cleaned, report) = sanitize_untrusted_text(family);
+        assert_eq!(cleaned, family);
+        assert!(!report.zero_width);
+    }
+
+    #[test]
+    fn test_sanitize_untrusted_text_strips_stray_zwj_not_joining_emoji() {
+        // A ZWJ between plain letters isn't a legitimate emoji sequence; it's exactly
+        // the kind of invisible character the request asked to strip.
+        let malicious = "pay\u{200D}pal";
+        let (cleaned, report) = sanitize_untrusted_text(malicious);
+        assert_eq!(cleaned, "paypal");
+        assert!(report.zero_width);
+    }
+
+    #[test]
+    fn test_sanitize_untrusted_text_flags_homoglyph_mix_without_stripping() {
+        // "аdmin" where the leading "а" is Cyrillic U+0430, not Latin "a".
+        let spoofed = "аdmin access";
+        let (cleaned, report) = sanitize_untrusted_text(spoofed);
+        assert_eq!(cleaned, spoofed);
+        assert!(report.mixed_script_homoglyph);
+    }
+
+    #[test]
+    fn test_sanitize_untrusted_text_clean_input() {
+        let clean = "Hello world 世界 🌍";
+        let (cleaned, report) = sanitize_untrusted_text(clean);
+        assert_eq!(cleaned, clean);
+        assert!(!report.is_suspicious());
+    }
+
+    #[test]
+    fn test_decode_and_sanitize_tool_bytes_transcodes_before_sanitizing() {
+        // 0x93/0x94 are Windows-1252 smart quotes; the sanitizer should only ever see
+        // the transcoded UTF-8 text, never the raw bytes.
+        let bytes = [0x93, b'h', b'i', 0x94];
+        let (text, encoding, report) = decode_and_sanitize_tool_bytes(&bytes);
+        assert_eq!(text, "\u{201C}hi\u{201D}");
+        assert_eq!(encoding, crate::encoding::DetectedEncoding::Windows1252);
+        assert!(!report.is_suspicious());
+    }
+
+    #[test]
+    fn test_tool_response_from_bytes_records_detected_encoding_in_metadata() {
+        // 0x93/0x94 are Windows-1252 smart quotes; not valid UTF-8 on their own.
+        let bytes = [0x93, b'h', b'i', 0x94];
+        let resp = tool_response_from_bytes("tool-1".to_string(), &bytes);
+
+        assert_eq!(
+            resp.metadata.get(DETECTED_ENCODING_METADATA_KEY).map(String::as_str),
+            Some("Windows-1252")
+        );
+
+        let result = resp.tool_result.unwrap();
+        let text = result.content[0].as_text().unwrap();
+        assert_eq!(text.text, "\u{201C}hi\u{201D}");
+    }
+
+    #[test]
+    fn test_tool_response_from_bytes_no_metadata_for_valid_utf8() {
+        let resp = tool_response_from_bytes("tool-2".to_string(), "hello".as_bytes());
+        assert!(resp
+            .metadata
+            .get(DETECTED_ENCODING_METADATA_KEY)
+            .is_none());
+    }
+
+    #[test]
+    fn test_tool_response_content_from_bytes_flows_through_message_display_pipeline() {
+        use rmcp::model::Role;
+
+        // A long, Shift-JIS-encoded tool output, as a subprocess might actually emit.
+        let chunk = [0x82u8, 0xb1, 0x82, 0xf1, 0x82, 0xc9, 0x82, 0xbf, 0x82, 0xcd];
+        let bytes: Vec<u8> = chunk.iter().cycle().take(20_000).copied().collect();
+
+        let content = tool_response_content_from_bytes("tool-3".to_string(), &bytes);
+        let message = Message::new(Role::Assistant, 0, vec![content]);
+
+        let MessageContent::ToolResponse(resp) = &message.content[0] else {
+            panic!("expected ToolResponse");
+        };
+        assert_eq!(
+            resp.metadata.get(DETECTED_ENCODING_METADATA_KEY).map(String::as_str),
+            Some("Shift_JIS")
+        );
+
+        // The existing display-truncation path must still operate on the transcoded,
+        // sanitized text produced from those raw bytes.
+        let truncated = truncate_message_for_display(&message);
+        let MessageContent::ToolResponse(truncated_resp) = &truncated.content[0] else {
+            panic!("expected ToolResponse");
+        };
+        let text = truncated_resp.tool_result.as_ref().unwrap().content[0]
+            .as_text()
+            .unwrap();
+        assert!(text.text.contains("output truncated"));
+        assert!(text.text.starts_with("こんにちは"));
+    }
+
     #[test]
     fn test_safe_truncate_ascii() {
         assert_eq!(safe_truncate("hello world", 20), "hello world");
@@ -211,11 +543,67 @@ mod tests {
         let long: String = "x".repeat(20_000);
         let result = truncate_tool_text_for_display(&long).unwrap();
         assert!(result.contains("output truncated"));
-        assert!(result.contains("10000 of 20000 characters"));
+        assert!(result.contains("10000 of 20000 columns"));
         // Should start with the first 10000 chars
         assert!(result.starts_with(&"x".repeat(10_000)));
     }
 
+    #[test]
+    fn test_safe_truncate_does_not_split_zwj_sequence() {
+        // The family emoji is a ZWJ sequence of several scalars, but it forms a
+        // *single* extended grapheme cluster, so a budget that fits one cluster
+        // must return it intact rather than splitting it mid-sequence.
+        let family = "👨‍👩‍👧";
+        assert_eq!(family.graphemes(true).count(), 1);
+        assert_eq!(safe_truncate(family, 10), family);
+        assert_eq!(safe_truncate(family, 1), family);
+
+        // Two separate family emoji are two clusters; truncating below that count
+        // must cut on a cluster boundary, not inside either ZWJ sequence. With the
+        // three-cluster ellipsis reserved, neither family survives.
+        let two_families = "👨‍👩‍👧👨‍👩‍👧";
+        assert_eq!(two_families.graphemes(true).count(), 2);
+        assert_eq!(safe_truncate(two_families, 1), "...");
+    }
+
+    #[test]
+    fn test_safe_truncate_does_not_split_combining_mark() {
+        // "é" as "e" + combining acute accent (U+0301) is a single grapheme cluster.
+        let combining = "e\u{0301}clair";
+        assert_eq!(safe_truncate(combining, 10), combining);
+        // Six clusters cut to four keeps one cluster, the whole "e" + U+0301.
+        assert_eq!(safe_truncate(combining, 4), "e\u{0301}...");
+    }
+
+    #[test]
+    fn test_truncate_to_width_wide_cjk() {
+        // Each CJK glyph below is 2 columns wide.
+        let wide = "你好世界";
+        assert_eq!(truncate_to_width(wide, 100, "…"), wide);
+        // Budget of 5 columns: "…" costs 1, leaving 4 columns for 2 glyphs.
+        assert_eq!(truncate_to_width(wide, 5, "…"), "你好…");
+    }
+
+    #[test]
+    fn test_truncate_to_width_reserves_ellipsis_only_when_truncating() {
+        let short = "hi";
+        // No truncation needed: ellipsis is not appended or budgeted for.
+        assert_eq!(truncate_to_width(short, 2, "..."), short);
+    }
+
+    #[test]
+    fn test_truncate_to_width_never_splits_cluster() {
+        let family = "👨‍👩‍👧 and friends";
+        let truncated = truncate_to_width(family, 3, "");
+        // The truncated output must be a clean prefix ending on a cluster boundary,
+        // never a half-written ZWJ sequence.
+        assert!(family.starts_with(&truncated));
+        assert!(truncated
+            .graphemes(true)
+            .zip(family.graphemes(true))
+            .all(|(a, b)| a == b));
+    }
+
     #[test]
     fn test_truncate_message_no_tool_response() {
         use rmcp::model::Role;